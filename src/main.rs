@@ -1,29 +1,118 @@
 
 
-use std::net::Ipv4Addr;
-use std::str::FromStr;
 use std::env;
 
-mod tracroute;
-use tracroute::run_traceroute;
+extern crate traceroute;
+use traceroute::tracroute::{reverse_lookup, resolve_target, ProbeMethod, TraceHop, Traceroute};
+
+static MAX_TTL: u8 = 64;
+static REQUESTS_PER_HOP: usize = 5;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut probe_method = ProbeMethod::IcmpEcho;
+    let mut prefer_v6 = false;
+    let mut resolve_hostnames = true;
+    let mut host = None;
 
-    if args.len() != 2 {
-        println!("Usage: traceroute <IPv4 host>", );
-        return;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "udp" => probe_method = ProbeMethod::Udp,
+            "-6" => prefer_v6 = true,
+            "-n" => resolve_hostnames = false,
+            _ if host.is_none() => host = Some(arg),
+            other => {
+                println!("Unknown argument: {}", other);
+                return;
+            },
+        }
     }
 
-    let ip_a;
+    let host = match host {
+        Some(host) => host,
+        None => {
+            println!("Usage: traceroute [-6] [-n] <host> [udp]");
+            return;
+        },
+    };
 
-    match Ipv4Addr::from_str(args[1].as_str()) {
-        Ok(parsed) => ip_a = parsed,
+    let ip_a = match resolve_target(host.as_str(), prefer_v6) {
+        Ok(addr) => addr,
         Err(err) => {
             println!("{}", err);
             return;
         },
+    };
+
+    let traceroute = Traceroute::new(ip_a)
+        .probe_method(probe_method)
+        .probes_per_hop(REQUESTS_PER_HOP)
+        .wait_time(1)
+        .max_ttl(MAX_TTL);
+
+    println!("{:>4}   {:<20} {:<15}", "Hop", "Host IP address", "Answer time");
+
+    let mut reached_destination = false;
+
+    for hop in traceroute {
+        match hop {
+            Ok(hop) => {
+                print_hop(&hop, resolve_hostnames);
+
+                if hop.reached_destination {
+                    reached_destination = true;
+                    break;
+                }
+            },
+            Err(err) => {
+                println!("{}", err);
+                return;
+            },
+        }
+    }
+
+    if !reached_destination {
+        println!("TTL value exceeded! Traceroute exits.", );
+    }
+}
+
+fn print_hop(hop: &TraceHop, resolve_hostnames: bool) {
+    let annotation = hop.reason.as_ref().map(|reason| format!(" {}", reason.annotation())).unwrap_or_default();
+
+    match hop.host {
+        None => {
+            /* 0 received packets */
+            println!("{:>3}.   {:^20} {:^15}{}", hop.ttl, "*", "*", annotation);
+        },
+        Some(host) if hop.rtts.len() < REQUESTS_PER_HOP => {
+            /* Received less packets than were sent. */
+            println!("{:>3}.   {:<20} {:^15}{}", hop.ttl, hop_display(host, resolve_hostnames), "*", annotation);
+        },
+        Some(host) => {
+            /* Received all packets */
+            let avrg_time = hop.rtts.iter()
+                .fold(std::time::Duration::from_secs(0), |acc, rtt| acc + *rtt) / hop.rtts.len() as u32;
+
+            println!("{:>3}.   {:<20} {:^15?}{}", hop.ttl, hop_display(host, resolve_hostnames), avrg_time, annotation);
+        },
+    }
+
+    if let Some(extensions) = &hop.extensions {
+        let labels: Vec<String> = extensions.mpls_labels.iter()
+            .map(|label| format!("L={} TTL={}", label.label, label.ttl))
+            .collect();
+
+        println!("      MPLS: {}", labels.join(", "));
+    }
+}
+
+/// Renders a hop's address as its PTR hostname when available, falling back
+/// to the raw IP when resolution is disabled or no PTR record exists.
+fn hop_display(host: std::net::IpAddr, resolve_hostnames: bool) -> String {
+    if resolve_hostnames {
+        if let Some(name) = reverse_lookup(host) {
+            return name;
+        }
     }
 
-    run_traceroute(ip_a, 5, 1);
+    host.to_string()
 }
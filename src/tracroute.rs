@@ -1,37 +1,391 @@
 extern crate pnet;
+extern crate dns_lookup;
 
 use pnet::util::checksum;
 use pnet::packet::{
     icmp::{
+        destination_unreachable,
+        IcmpCode,
         echo_reply::EchoReplyPacket,
         echo_request::{MutableEchoRequestPacket, EchoRequestPacket},
         IcmpPacket,
-        IcmpType,
         IcmpTypes,
     },
+    icmpv6::{
+        echo_reply::EchoReplyPacket as Echov6ReplyPacket,
+        echo_request::{MutableEchoRequestPacket as MutableEchov6RequestPacket, EchoRequestPacket as Echov6RequestPacket},
+        Icmpv6Packet,
+        Icmpv6Types,
+    },
     ip::IpNextHeaderProtocols,
     ipv4::MutableIpv4Packet,
+    udp::{MutableUdpPacket, UdpPacket},
     MutablePacket,
     Packet,
 };
-use pnet::transport::{icmp_packet_iter, transport_channel, TransportChannelType::Layer3};
+use pnet::transport::{
+    icmp_packet_iter,
+    icmpv6_packet_iter,
+    transport_channel,
+    TransportChannelType::Layer3,
+    TransportReceiver,
+    TransportSender,
+};
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::str::FromStr;
 use std::time::{Instant, Duration};
 
 
 static IPV4_HEADER_LEN: u32 = 21;
 static ICMP_HEADER_LEN: u32 = 8;
 static ICMP_PAYLOAD_LEN: u32 = 32;
-static MAX_TTL: usize = 64;
+static UDP_HEADER_LEN: u32 = 8;
+static UDP_PAYLOAD_LEN: u32 = 32;
+static UDP_SRC_PORT: u16 = 33434;
+static UDP_DEST_PORT_BASE: u16 = 33434;
+static DEFAULT_MAX_TTL: u8 = 64;
+static DEFAULT_REQUESTS_PER_HOP: usize = 5;
+static DEFAULT_WAIT_TIME: u64 = 1;
+static DEFAULT_FIRST_TTL: u8 = 1;
+
+/* Quoted-original-datagram offsets inside the ICMP(v6) message: header length
+   plus the quoted IP header length, both of which differ between families. */
+static ICMPV4_QUOTE_OFFSET: usize = 28;
+static ICMPV6_QUOTE_OFFSET: usize = 48;
+
+
+/// Everything that can go wrong running a traceroute, surfaced instead of
+/// panicking so callers can match on it and decide how to recover.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TracerouteError {
+    /// Raw sockets need root or `CAP_NET_RAW`; this is the most actionable
+    /// failure, so it's kept distinct from other channel setup errors.
+    PermissionDenied,
+    ChannelCreation(String),
+    PacketConstruction(String),
+    Send(String),
+    Receive(String),
+    MalformedReply(String),
+    Timeout,
+    Resolution(String),
+}
+
+impl fmt::Display for TracerouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TracerouteError::PermissionDenied =>
+                write!(f, "permission denied opening a raw socket (requires root or CAP_NET_RAW)"),
+            TracerouteError::ChannelCreation(msg) => write!(f, "failed to create transport channel: {}", msg),
+            TracerouteError::PacketConstruction(msg) => write!(f, "failed to construct probe packet: {}", msg),
+            TracerouteError::Send(msg) => write!(f, "failed to send probe: {}", msg),
+            TracerouteError::Receive(msg) => write!(f, "failed to receive reply: {}", msg),
+            TracerouteError::MalformedReply(msg) => write!(f, "received malformed reply: {}", msg),
+            TracerouteError::Timeout => write!(f, "timed out waiting for a reply"),
+            TracerouteError::Resolution(msg) => write!(f, "failed to resolve host: {}", msg),
+        }
+    }
+}
+
+impl Error for TracerouteError {}
+
+fn wrap_channel_error(err: io::Error) -> TracerouteError {
+    if err.kind() == io::ErrorKind::PermissionDenied {
+        TracerouteError::PermissionDenied
+    } else {
+        TracerouteError::ChannelCreation(err.to_string())
+    }
+}
+
+/// Slices `buf` at `offset`, reporting a `MalformedReply` instead of
+/// panicking when the reply is too short to contain what we expect there.
+fn slice_from(buf: &[u8], offset: usize) -> Result<&[u8], TracerouteError> {
+    if buf.len() < offset {
+        return Err(TracerouteError::MalformedReply(
+            format!("reply too short: expected at least {} bytes, got {}", offset, buf.len())));
+    }
+    Ok(&buf[offset..])
+}
+
+/// Resolves a CLI-supplied target to an `IpAddr`, accepting both literal
+/// addresses and hostnames. Literals are parsed directly; hostnames go
+/// through the system resolver, preferring an IPv6 address when `prefer_v6`
+/// is set and an IPv4 address otherwise; if none of the preferred family is
+/// returned, the first address returned is taken.
+pub fn resolve_target(host: &str, prefer_v6: bool) -> Result<IpAddr, TracerouteError> {
+    if let Ok(addr) = IpAddr::from_str(host) {
+        return Ok(addr);
+    }
+
+    let addrs: Vec<IpAddr> = (host, 0).to_socket_addrs()
+        .map_err(|err| TracerouteError::Resolution(err.to_string()))?
+        .map(|socket_addr| socket_addr.ip())
+        .collect();
+
+    let resolved = if prefer_v6 {
+        addrs.iter().find(|addr| addr.is_ipv6()).or_else(|| addrs.first())
+    } else {
+        addrs.iter().find(|addr| addr.is_ipv4()).or_else(|| addrs.first())
+    };
+
+    resolved.copied().ok_or_else(|| TracerouteError::Resolution(format!("no address found for host '{}'", host)))
+}
+
+/// Reverse-resolves `addr` to a PTR hostname for display. `None` is returned
+/// whenever the lookup fails, whatever the cause; callers fall back to the
+/// raw address in that case.
+pub fn reverse_lookup(addr: IpAddr) -> Option<String> {
+    dns_lookup::lookup_addr(&addr).ok()
+}
+
+/// Selects which kind of probe packet is sent to each hop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeMethod {
+    IcmpEcho,
+    Udp,
+}
+
+/// Family-agnostic classification of a hop reply, since `IcmpType` and
+/// `Icmpv6Type` are distinct pnet types that can't be compared to each other.
+/// `PortUnreachable` is kept separate from the broader `Unreachable` since
+/// the former is the UDP probe's success signal, not a failure to report.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReplyKind {
+    TimeExceeded,
+    EchoReply,
+    PortUnreachable,
+    Unreachable,
+    Redirect,
+    ParameterProblem,
+}
+
+/// A single entry of an RFC 4950 MPLS Label Stack extension object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MplsLabel {
+    pub label: u32,
+    pub experimental: u8,
+    pub bottom_of_stack: bool,
+    pub ttl: u8,
+}
+
+/// RFC 4884 ICMP multi-part extensions parsed out of a Time Exceeded reply.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Extensions {
+    pub mpls_labels: Vec<MplsLabel>,
+}
+
+/// Human-readable classification of a non-timeout, non-success ICMP reply,
+/// along with the traditional single-letter annotation classic traceroute
+/// prints next to a hop (`!N`, `!H`, ...) to flag it as administratively
+/// filtered or otherwise abnormal rather than a plain timeout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplyReason {
+    NetworkUnreachable,
+    HostUnreachable,
+    ProtocolUnreachable,
+    FragmentationNeeded,
+    SourceRouteFailed,
+    CommunicationAdministrativelyProhibited,
+    OtherUnreachable(u8),
+    Redirect,
+    ParameterProblem,
+}
 
+impl ReplyReason {
+    pub fn annotation(&self) -> String {
+        match self {
+            ReplyReason::NetworkUnreachable => "!N".to_string(),
+            ReplyReason::HostUnreachable => "!H".to_string(),
+            ReplyReason::ProtocolUnreachable => "!P".to_string(),
+            ReplyReason::FragmentationNeeded => "!F".to_string(),
+            ReplyReason::SourceRouteFailed => "!S".to_string(),
+            ReplyReason::CommunicationAdministrativelyProhibited => "!X".to_string(),
+            ReplyReason::OtherUnreachable(code) => format!("!<{}>", code),
+            ReplyReason::Redirect => "!R".to_string(),
+            ReplyReason::ParameterProblem => "!PP".to_string(),
+        }
+    }
+}
+
+fn destination_unreachable_reason(code: IcmpCode) -> ReplyReason {
+    match code.0 {
+        0 => ReplyReason::NetworkUnreachable,
+        1 => ReplyReason::HostUnreachable,
+        2 => ReplyReason::ProtocolUnreachable,
+        4 => ReplyReason::FragmentationNeeded,
+        5 => ReplyReason::SourceRouteFailed,
+        13 => ReplyReason::CommunicationAdministrativelyProhibited,
+        other => ReplyReason::OtherUnreachable(other),
+    }
+}
 
 #[derive(Clone, Debug)]
 struct HopReply {
     hop_addr: IpAddr,
     reply_time: Duration,
-    reply_type: IcmpType,
-    sequence_number: u16
+    reply_type: ReplyKind,
+    sequence_number: u16,
+    extensions: Option<Extensions>,
+    reason: Option<ReplyReason>,
+}
+
+/// One TTL round's aggregated result, as yielded by `Traceroute`'s
+/// `Iterator` implementation.
+#[derive(Clone, Debug)]
+pub struct TraceHop {
+    pub ttl: u8,
+    pub host: Option<IpAddr>,
+    pub rtts: Vec<Duration>,
+    pub reached_destination: bool,
+    pub extensions: Option<Extensions>,
+    pub reason: Option<ReplyReason>,
+}
+
+/// The open transport channels for a traceroute, kept alive across rounds.
+/// Kept apart from `Traceroute` itself since the two address families need
+/// differently-bound sockets (a UDP-or-ICMP sender paired with an ICMP
+/// receiver for IPv4, an ICMPv6 sender paired with an ICMPv6 receiver for
+/// IPv6).
+enum Channels {
+    V4 { tx: TransportSender, rx: TransportReceiver },
+    V6 { tx: TransportSender, rx: TransportReceiver },
+}
+
+/// Builder-configured traceroute run. Each call to `next()` sends one TTL
+/// round's worth of probes, waits for replies and returns their aggregated
+/// result; iteration ends once the destination replies, `max_ttl` is
+/// exceeded, or a round returns an error.
+pub struct Traceroute {
+    dest: IpAddr,
+    probe_method: ProbeMethod,
+    requests_per_hop: usize,
+    wait_time: u64,
+    max_ttl: u8,
+    ttl: u8,
+    finished: bool,
+    channels: Option<Channels>,
+}
+
+impl Traceroute {
+    pub fn new(dest: IpAddr) -> Self {
+        Traceroute {
+            dest,
+            probe_method: ProbeMethod::IcmpEcho,
+            requests_per_hop: DEFAULT_REQUESTS_PER_HOP,
+            wait_time: DEFAULT_WAIT_TIME,
+            max_ttl: DEFAULT_MAX_TTL,
+            ttl: DEFAULT_FIRST_TTL,
+            finished: false,
+            channels: None,
+        }
+    }
+
+    pub fn probe_method(mut self, probe_method: ProbeMethod) -> Self {
+        self.probe_method = probe_method;
+        self
+    }
+
+    pub fn probes_per_hop(mut self, requests_per_hop: usize) -> Self {
+        self.requests_per_hop = requests_per_hop;
+        self
+    }
+
+    pub fn wait_time(mut self, wait_time: u64) -> Self {
+        self.wait_time = wait_time;
+        self
+    }
+
+    pub fn max_ttl(mut self, max_ttl: u8) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// Clamped to at least 1: TTL/hop-limit windows are computed as
+    /// `(ttl - 1) * requests_per_hop`, which would underflow for 0.
+    pub fn first_ttl(mut self, first_ttl: u8) -> Self {
+        self.ttl = first_ttl.max(1);
+        self
+    }
+}
+
+impl Iterator for Traceroute {
+    type Item = Result<TraceHop, TracerouteError>;
+
+    fn next(&mut self) -> Option<Result<TraceHop, TracerouteError>> {
+        if self.finished || self.ttl > self.max_ttl {
+            return None;
+        }
+
+        if self.channels.is_none() {
+            match open_channels(self.dest, self.probe_method) {
+                Ok(channels) => self.channels = Some(channels),
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                },
+            }
+        }
+        let channels = self.channels.as_mut().expect("channels were just opened");
+
+        let hop = match (self.dest, channels) {
+            (IpAddr::V4(dest), Channels::V4 { tx, rx }) => run_round_v4(
+                dest, self.ttl, self.requests_per_hop, self.wait_time, self.probe_method, tx, rx),
+            (IpAddr::V6(dest), Channels::V6 { tx, rx }) => run_round_v6(
+                dest, self.ttl, self.requests_per_hop, self.wait_time, tx, rx),
+            _ => unreachable!("channels always match the destination's address family"),
+        };
+
+        match hop {
+            Ok(hop) => {
+                /* Checked here rather than only by `ttl > max_ttl` above, since
+                   the saturating increment never passes a `max_ttl` of 255. */
+                self.finished = hop.reached_destination || self.ttl >= self.max_ttl;
+                self.ttl = self.ttl.saturating_add(1);
+                Some(Ok(hop))
+            },
+            Err(err) => {
+                self.finished = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+fn open_channels(dest: IpAddr, probe_method: ProbeMethod) -> Result<Channels, TracerouteError> {
+    match dest {
+        IpAddr::V4(_) => {
+            let tx_protocol = match probe_method {
+                ProbeMethod::IcmpEcho => IpNextHeaderProtocols::Icmp,
+                ProbeMethod::Udp => IpNextHeaderProtocols::Udp,
+            };
+
+            let (tx, _) = transport_channel(1024, Layer3(tx_protocol))
+                .map_err(wrap_channel_error)?;
+
+            /* Replies always arrive as ICMP, regardless of the probe method used to send. */
+            let (_, rx) = transport_channel(1024, Layer3(IpNextHeaderProtocols::Icmp))
+                .map_err(wrap_channel_error)?;
+
+            Ok(Channels::V4 { tx, rx })
+        },
+        IpAddr::V6(_) => {
+            if probe_method != ProbeMethod::IcmpEcho {
+                return Err(TracerouteError::PacketConstruction(
+                    "UDP probe method is not supported over IPv6 yet".into()));
+            }
+
+            let (tx, _) = transport_channel(1024, Layer3(IpNextHeaderProtocols::Icmpv6))
+                .map_err(wrap_channel_error)?;
+
+            let (_, rx) = transport_channel(1024, Layer3(IpNextHeaderProtocols::Icmpv6))
+                .map_err(wrap_channel_error)?;
+
+            Ok(Channels::V6 { tx, rx })
+        },
+    }
 }
 
 fn create_icmp_packet<'a>(
@@ -40,10 +394,10 @@ fn create_icmp_packet<'a>(
     dest: Ipv4Addr,
     ttl: u8,
     sequence_number: u16,
-) -> MutableIpv4Packet<'a> {
+) -> Result<MutableIpv4Packet<'a>, TracerouteError> {
     let mut ipv4_packet = MutableIpv4Packet::new(buf_ip)
-        .expect("Error creating IPv4 packet");
-    
+        .ok_or_else(|| TracerouteError::PacketConstruction("IPv4 packet buffer too small".into()))?;
+
     ipv4_packet.set_version(4);
     ipv4_packet.set_header_length(IPV4_HEADER_LEN as u8);
     ipv4_packet.set_total_length((IPV4_HEADER_LEN + ICMP_HEADER_LEN + ICMP_PAYLOAD_LEN) as u16);
@@ -52,7 +406,7 @@ fn create_icmp_packet<'a>(
     ipv4_packet.set_destination(dest);
 
     let mut icmp_packet = MutableEchoRequestPacket::new(buf_icmp)
-        .expect("Error creating ICMP packet");
+        .ok_or_else(|| TracerouteError::PacketConstruction("ICMP packet buffer too small".into()))?;
 
     icmp_packet.set_icmp_type(IcmpTypes::EchoRequest);
     icmp_packet.set_sequence_number(sequence_number);
@@ -62,128 +416,408 @@ fn create_icmp_packet<'a>(
     icmp_packet.set_checksum(checksum);
     ipv4_packet.set_payload(icmp_packet.packet_mut());
 
-    ipv4_packet
+    Ok(ipv4_packet)
+}
+
+/// Builds a UDP probe aimed at a high, unlikely-to-be-open destination port.
+/// The probe's index is folded into the destination port (`UDP_DEST_PORT_BASE`
+/// + `sequence_number`) since, unlike ICMP echo, a UDP header carries no
+/// sequence number of its own - routers quote this port back in the
+/// Destination Unreachable reply, which is how we recover it again.
+fn create_udp_packet<'a>(
+    buf_ip: &'a mut [u8],
+    buf_udp: &'a mut [u8],
+    dest: Ipv4Addr,
+    ttl: u8,
+    sequence_number: u16,
+) -> Result<MutableIpv4Packet<'a>, TracerouteError> {
+    let mut ipv4_packet = MutableIpv4Packet::new(buf_ip)
+        .ok_or_else(|| TracerouteError::PacketConstruction("IPv4 packet buffer too small".into()))?;
+
+    ipv4_packet.set_version(4);
+    ipv4_packet.set_header_length(IPV4_HEADER_LEN as u8);
+    ipv4_packet.set_total_length((IPV4_HEADER_LEN + UDP_HEADER_LEN + UDP_PAYLOAD_LEN) as u16);
+    ipv4_packet.set_ttl(ttl);
+    ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ipv4_packet.set_destination(dest);
+
+    let mut udp_packet = MutableUdpPacket::new(buf_udp)
+        .ok_or_else(|| TracerouteError::PacketConstruction("UDP packet buffer too small".into()))?;
+
+    udp_packet.set_source(UDP_SRC_PORT);
+    udp_packet.set_destination(UDP_DEST_PORT_BASE + sequence_number);
+    udp_packet.set_length((UDP_HEADER_LEN + UDP_PAYLOAD_LEN) as u16);
+
+    // The real source address isn't known until the kernel routes the
+    // packet, so a checksum computed now would be checked against the wrong
+    // pseudo-header and the probe would be dropped instead of eliciting a
+    // reply. Zero is a valid "no checksum" per RFC 768.
+    udp_packet.set_checksum(0);
+
+    ipv4_packet.set_payload(udp_packet.packet_mut());
+
+    Ok(ipv4_packet)
 }
 
-fn process_reply(reply: IcmpPacket, host: IpAddr, duration: Duration) -> Option<HopReply> {
+/// Builds a bare ICMPv6 Echo Request. Unlike the IPv4 path there is no IP
+/// header to construct here: a `Layer3(Icmpv6)` raw socket takes just the
+/// ICMPv6 message and the kernel fills in the IPv6 header, including the
+/// checksum (which requires the pseudo-header source address we don't know
+/// ahead of a `connect()`/`bind()`), so `checksum` is left at zero.
+fn create_icmpv6_packet<'a>(
+    buf_icmp: &'a mut [u8],
+    sequence_number: u16,
+) -> Result<MutableEchov6RequestPacket<'a>, TracerouteError> {
+    let mut icmp_packet = MutableEchov6RequestPacket::new(buf_icmp)
+        .ok_or_else(|| TracerouteError::PacketConstruction("ICMPv6 packet buffer too small".into()))?;
+
+    icmp_packet.set_icmpv6_type(Icmpv6Types::EchoRequest);
+    icmp_packet.set_sequence_number(sequence_number);
+    icmp_packet.set_checksum(0);
+
+    Ok(icmp_packet)
+}
+
+fn process_reply(reply: IcmpPacket, host: IpAddr, duration: Duration) -> Result<Option<HopReply>, TracerouteError> {
     match reply.get_icmp_type() {
         IcmpTypes::TimeExceeded => {
-            /* 
+            /*
                 Time exceeded message returns IP header and first 8 bytes of original datagram's payload, so..
                 Original echo request is on 28 byte offset.
             */
-            let request_packet = EchoRequestPacket::new(&reply.packet()[28..])
-                .expect("Parsing echo request packet failed!");
+            let request_packet = EchoRequestPacket::new(slice_from(reply.packet(), ICMPV4_QUOTE_OFFSET)?)
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse quoted echo request".into()))?;
 
-            Some( HopReply {
+            Ok(Some( HopReply {
                 hop_addr: host,
                 reply_time: duration,
-                reply_type: IcmpTypes::TimeExceeded,
+                reply_type: ReplyKind::TimeExceeded,
                 sequence_number: request_packet.get_sequence_number(),
-            })
+                extensions: parse_extensions(reply.packet()),
+                reason: None,
+            }))
         },
         IcmpTypes::EchoReply => {
-            let reply_packet = EchoReplyPacket::new(&reply.packet())
-                .expect("Parsing echo reply packet failed!");
+            let reply_packet = EchoReplyPacket::new(reply.packet())
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse echo reply".into()))?;
 
-            Some( HopReply {
+            Ok(Some( HopReply {
                 hop_addr: host,
                 reply_time: duration,
-                reply_type: IcmpTypes::EchoReply,
+                reply_type: ReplyKind::EchoReply,
                 sequence_number: reply_packet.get_sequence_number(),
-            })
+                extensions: None,
+                reason: None,
+            }))
+        },
+        IcmpTypes::DestinationUnreachable if reply.get_icmp_code() == destination_unreachable::IcmpCodes::DestinationPortUnreachable => {
+            /*
+                Port unreachable message quotes the IP header and first 8 bytes of the
+                original datagram's payload, same 28 byte offset as Time Exceeded. For a
+                UDP probe those 8 bytes are the whole UDP header, so the destination port
+                we encoded the sequence number into is recoverable from it.
+            */
+            let udp_packet = UdpPacket::new(slice_from(reply.packet(), ICMPV4_QUOTE_OFFSET)?)
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse quoted UDP header".into()))?;
+
+            /* The Layer3 socket sees every ICMP message on the host, not just
+               replies to our own probes: a Port Unreachable for someone
+               else's UDP traffic can quote a destination port below our
+               base and must not be treated as ours. */
+            let sequence_number = match udp_packet.get_destination().checked_sub(UDP_DEST_PORT_BASE) {
+                Some(sequence_number) => sequence_number,
+                None => return Ok(None),
+            };
+
+            Ok(Some( HopReply {
+                hop_addr: host,
+                reply_time: duration,
+                reply_type: ReplyKind::PortUnreachable,
+                sequence_number,
+                extensions: None,
+                reason: None,
+            }))
+        },
+        IcmpTypes::DestinationUnreachable => {
+            /* Any other Destination Unreachable code: not a probe success, but
+               worth reporting why the hop filtered or rejected us. The quoted
+               probe is recovered the same way as the Time Exceeded branch. */
+            let request_packet = EchoRequestPacket::new(slice_from(reply.packet(), ICMPV4_QUOTE_OFFSET)?)
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse quoted echo request".into()))?;
+
+            Ok(Some( HopReply {
+                hop_addr: host,
+                reply_time: duration,
+                reply_type: ReplyKind::Unreachable,
+                sequence_number: request_packet.get_sequence_number(),
+                extensions: None,
+                reason: Some(destination_unreachable_reason(reply.get_icmp_code())),
+            }))
         },
-        _ => None,
+        IcmpTypes::RedirectMessage => {
+            let request_packet = EchoRequestPacket::new(slice_from(reply.packet(), ICMPV4_QUOTE_OFFSET)?)
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse quoted echo request".into()))?;
+
+            Ok(Some( HopReply {
+                hop_addr: host,
+                reply_time: duration,
+                reply_type: ReplyKind::Redirect,
+                sequence_number: request_packet.get_sequence_number(),
+                extensions: None,
+                reason: Some(ReplyReason::Redirect),
+            }))
+        },
+        IcmpTypes::ParameterProblem => {
+            let request_packet = EchoRequestPacket::new(slice_from(reply.packet(), ICMPV4_QUOTE_OFFSET)?)
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse quoted echo request".into()))?;
+
+            Ok(Some( HopReply {
+                hop_addr: host,
+                reply_time: duration,
+                reply_type: ReplyKind::ParameterProblem,
+                sequence_number: request_packet.get_sequence_number(),
+                extensions: None,
+                reason: Some(ReplyReason::ParameterProblem),
+            }))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Parses RFC 4884/4950 ICMP multi-part extensions out of a Time Exceeded
+/// message, most usefully an MPLS Label Stack object. The ICMP header's
+/// "length" byte (offset 5) gives the quoted original datagram's length in
+/// 32-bit words; the extension structure - a 4-byte header followed by one
+/// or more class/c-type objects - starts right after that padded region.
+/// Returns `None` if the length byte is zero (no extensions present), the
+/// header's version isn't 2, or nothing we understand is found.
+fn parse_extensions(icmp_message: &[u8]) -> Option<Extensions> {
+    if icmp_message.len() <= ICMP_HEADER_LEN as usize {
+        return None;
+    }
+
+    let length_words = icmp_message[5] as usize;
+    if length_words == 0 {
+        return None;
+    }
+
+    let ext_offset = ICMP_HEADER_LEN as usize + length_words * 4;
+    if icmp_message.len() < ext_offset + 4 {
+        return None;
+    }
+
+    let ext_header = &icmp_message[ext_offset..];
+    let version = ext_header[0] >> 4;
+    if version != 2 {
+        return None;
+    }
+
+    let mut objects = &ext_header[4..];
+    let mut mpls_labels = Vec::new();
+
+    while objects.len() >= 4 {
+        let object_len = u16::from_be_bytes([objects[0], objects[1]]) as usize;
+        if object_len < 4 || object_len > objects.len() {
+            break;
+        }
+
+        let class_num = objects[2];
+        if class_num == 1 {
+            /* MPLS Label Stack: each 4-byte entry is label(20) | exp(3) | S(1) | ttl(8). */
+            for entry in objects[4..object_len].chunks_exact(4) {
+                let packed = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+
+                mpls_labels.push(MplsLabel {
+                    label: packed >> 12,
+                    experimental: ((packed >> 9) & 0x7) as u8,
+                    bottom_of_stack: (packed >> 8) & 0x1 != 0,
+                    ttl: (packed & 0xFF) as u8,
+                });
+            }
+        }
+
+        objects = &objects[object_len..];
+    }
+
+    if mpls_labels.is_empty() {
+        None
+    } else {
+        Some(Extensions { mpls_labels })
     }
 }
 
-pub fn run_traceroute(dest: Ipv4Addr, requests_per_hop: usize, wait_time: u64) {
-    let (mut tx, mut rx) = transport_channel(
-        1024,
-        Layer3(IpNextHeaderProtocols::Icmp))
-        .expect("Creating transport channel failed!");
+/// Family-aware twin of `process_reply` for ICMPv6. The quoted original
+/// datagram sits 48 bytes in rather than 28: the ICMPv6 Time Exceeded header
+/// is 8 bytes (same as ICMPv4's) but the quoted IPv6 header it wraps is a
+/// fixed 40 bytes with no options and no header checksum field to skip.
+fn process_reply_v6(reply: Icmpv6Packet, host: IpAddr, duration: Duration) -> Result<Option<HopReply>, TracerouteError> {
+    match reply.get_icmpv6_type() {
+        Icmpv6Types::TimeExceeded => {
+            let request_packet = Echov6RequestPacket::new(slice_from(reply.packet(), ICMPV6_QUOTE_OFFSET)?)
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse quoted echo request".into()))?;
+
+            Ok(Some( HopReply {
+                hop_addr: host,
+                reply_time: duration,
+                reply_type: ReplyKind::TimeExceeded,
+                sequence_number: request_packet.get_sequence_number(),
+                extensions: None,
+                reason: None,
+            }))
+        },
+        Icmpv6Types::EchoReply => {
+            let reply_packet = Echov6ReplyPacket::new(reply.packet())
+                .ok_or_else(|| TracerouteError::MalformedReply("could not parse echo reply".into()))?;
 
-    let mut rx = icmp_packet_iter(&mut rx);
+            Ok(Some( HopReply {
+                hop_addr: host,
+                reply_time: duration,
+                reply_type: ReplyKind::EchoReply,
+                sequence_number: reply_packet.get_sequence_number(),
+                extensions: None,
+                reason: None,
+            }))
+        },
+        _ => Ok(None),
+    }
+}
 
-    let mut is_destionantion = false;
-    let mut ttl: usize = 1;
-    let packet_time_sec = Duration::from_secs(wait_time); 
+fn run_round_v4(
+    dest: Ipv4Addr,
+    ttl: u8,
+    requests_per_hop: usize,
+    wait_time: u64,
+    probe_method: ProbeMethod,
+    tx: &mut TransportSender,
+    rx: &mut TransportReceiver,
+) -> Result<TraceHop, TracerouteError> {
+    let mut rx_iter = icmp_packet_iter(rx);
 
     let mut buf_ip = [0u8; 64];
-    let mut buf_icmp = [0u8; 40];
+    let mut buf_payload = [0u8; 40];
 
-    println!("{:>4}   {:<20} {:<15}", "Hop", "Host IP address", "Answer time");
+    let packet_time_sec = Duration::from_secs(wait_time);
+    let timer_start = Instant::now();
 
-    while !is_destionantion && ttl <= MAX_TTL {
-        let mut replies: Vec<HopReply> = Vec::with_capacity(requests_per_hop);
+    for i in 0..requests_per_hop {
+        let sequence_number = ((ttl as usize - 1) * requests_per_hop + i) as u16;
 
-        let timer_start = Instant::now();
+        let probe_packet = match probe_method {
+            ProbeMethod::IcmpEcho => create_icmp_packet(&mut buf_ip, &mut buf_payload, dest, ttl, sequence_number)?,
+            ProbeMethod::Udp => create_udp_packet(&mut buf_ip, &mut buf_payload, dest, ttl, sequence_number)?,
+        };
 
-        for i in 0..requests_per_hop {
-            let icmp_packet = create_icmp_packet(
-                &mut buf_ip, 
-                &mut buf_icmp, 
-                dest, 
-                ttl as u8,
-                ((ttl - 1) * requests_per_hop + i) as u16);
+        tx.send_to(probe_packet, IpAddr::V4(dest))
+            .map_err(|err| TracerouteError::Send(err.to_string()))?;
+    }
 
-            tx.send_to(icmp_packet, std::net::IpAddr::V4(dest))
-                .expect("Sending packet failed!");
-        }
+    let mut replies: Vec<HopReply> = Vec::with_capacity(requests_per_hop);
 
-        loop {
-            let waiting_time = timer_start.elapsed();
+    loop {
+        let waiting_time = timer_start.elapsed();
 
-            if waiting_time > packet_time_sec { break; }
+        if waiting_time > packet_time_sec { break; }
 
-            let receiving_time = packet_time_sec - timer_start.elapsed();
+        let receiving_time = packet_time_sec - timer_start.elapsed();
 
-            match rx.next_with_timeout(receiving_time) {
-                Ok(Some((reply, host))) => {
-                    /* In reply first 20 bytes encode IP header. */
-                    let icmp_header = IcmpPacket::new(&reply.packet()[20..])
-                        .expect("Parsing reply failed!");
+        match rx_iter.next_with_timeout(receiving_time) {
+            Ok(Some((reply, host))) => {
+                /* In reply first 20 bytes encode IP header. */
+                let icmp_header = IcmpPacket::new(slice_from(reply.packet(), 20)?)
+                    .ok_or_else(|| TracerouteError::MalformedReply("could not parse ICMP header".into()))?;
 
-                    if let Some(hop) = process_reply(icmp_header, host, timer_start.elapsed()) {
-                        replies.push(hop);
-                    }
-                }, 
-                Ok(None) => break, // time expired
-                Err(err) => panic!("Receiving packet error:\n{:?}", err),
-            }
+                if let Some(hop) = process_reply(icmp_header, host, timer_start.elapsed())? {
+                    replies.push(hop);
+                }
+            },
+            Ok(None) => break, // time expired
+            Err(err) => return Err(wrap_receive_error(err)),
         }
-        
-        /* Filter out all previous unhandled packets. */
-        let replies: Vec<HopReply> = replies.into_iter().filter( |reply| {
-            let sequence_number = reply.sequence_number as usize;
-            (ttl - 1) * requests_per_hop <= sequence_number && sequence_number < ttl * requests_per_hop
-        }).collect();
+    }
+
+    let target = match probe_method {
+        ProbeMethod::IcmpEcho => ReplyKind::EchoReply,
+        ProbeMethod::Udp => ReplyKind::PortUnreachable,
+    };
 
-        /* Check we got reply from destination host. */
-        is_destionantion = replies.iter().any(|reply| reply.reply_type == IcmpTypes::EchoReply);
+    Ok(to_trace_hop(ttl, requests_per_hop, replies, target))
+}
 
-        if replies.is_empty() {
-            /* 0 received packets */
-            println!("{:>3}.   {:^20} {:^15}", ttl, "*", "*");
+fn run_round_v6(
+    dest: Ipv6Addr,
+    ttl: u8,
+    requests_per_hop: usize,
+    wait_time: u64,
+    tx: &mut TransportSender,
+    rx: &mut TransportReceiver,
+) -> Result<TraceHop, TracerouteError> {
+    let mut rx_iter = icmpv6_packet_iter(rx);
 
-        }
-        else if replies.len() < requests_per_hop {
-            /* Received less packets than were sent. */
-            println!("{:>3}.   {:<20} {:^15}", ttl, replies[0].hop_addr.to_string(), "*");
-        }
-        else if replies.len() == requests_per_hop {
-            /* Received all packets */
-            let avrg_time = replies.iter()
-                .fold(Duration::from_secs(0), |acc, reply| acc + reply.reply_time) / requests_per_hop as u32;
+    let mut buf_icmp = [0u8; 16];
+
+    let packet_time_sec = Duration::from_secs(wait_time);
+    let timer_start = Instant::now();
+
+    /* Hop Limit is the IPv6 analogue of TTL; it's a socket option here since
+       the kernel, not us, builds the IPv6 header on a Layer3(Icmpv6) socket. */
+    tx.set_ttl(ttl)
+        .map_err(|err| TracerouteError::Send(err.to_string()))?;
+
+    for i in 0..requests_per_hop {
+        let sequence_number = ((ttl as usize - 1) * requests_per_hop + i) as u16;
+
+        let probe_packet = create_icmpv6_packet(&mut buf_icmp, sequence_number)?;
+
+        tx.send_to(probe_packet, IpAddr::V6(dest))
+            .map_err(|err| TracerouteError::Send(err.to_string()))?;
+    }
+
+    let mut replies: Vec<HopReply> = Vec::with_capacity(requests_per_hop);
+
+    loop {
+        let waiting_time = timer_start.elapsed();
 
-            println!("{:>3}.   {:<20} {:^15?}", ttl, replies[0].hop_addr.to_string(), avrg_time);
+        if waiting_time > packet_time_sec { break; }
+
+        let receiving_time = packet_time_sec - timer_start.elapsed();
+
+        match rx_iter.next_with_timeout(receiving_time) {
+            Ok(Some((reply, host))) => {
+                if let Some(hop) = process_reply_v6(reply, host, timer_start.elapsed())? {
+                    replies.push(hop);
+                }
+            },
+            Ok(None) => break, // time expired
+            Err(err) => return Err(wrap_receive_error(err)),
         }
-        
-        ttl += 1;
     }
-    
-    if ttl > MAX_TTL {
-        println!("TTL value exceeded! Traceroute exits.", );
+
+    Ok(to_trace_hop(ttl, requests_per_hop, replies, ReplyKind::EchoReply))
+}
+
+fn wrap_receive_error(err: io::Error) -> TracerouteError {
+    match err.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TracerouteError::Timeout,
+        _ => TracerouteError::Receive(err.to_string()),
     }
-    
-}
\ No newline at end of file
+}
+
+/// Filters out stale replies from earlier rounds and aggregates what's left
+/// into the iterator's public `TraceHop` item.
+fn to_trace_hop(ttl: u8, requests_per_hop: usize, replies: Vec<HopReply>, target: ReplyKind) -> TraceHop {
+    let window_start = (ttl as usize - 1) * requests_per_hop;
+    let window_end = ttl as usize * requests_per_hop;
+
+    let replies: Vec<HopReply> = replies.into_iter().filter( |reply| {
+        let sequence_number = reply.sequence_number as usize;
+        window_start <= sequence_number && sequence_number < window_end
+    }).collect();
+
+    let reached_destination = replies.iter().any(|reply| reply.reply_type == target);
+    let host = replies.first().map(|reply| reply.hop_addr);
+    let rtts = replies.iter().map(|reply| reply.reply_time).collect();
+    let extensions = replies.iter().find_map(|reply| reply.extensions.clone());
+    let reason = replies.iter().find_map(|reply| reply.reason);
+
+    TraceHop { ttl, host, rtts, reached_destination, extensions, reason }
+}